@@ -1,18 +1,190 @@
 use openssl::ssl;
+use openssl::x509;
+use std::cmp;
 use std::io::net::ip::Port;
 use std::io::net::tcp;
 use std::io::net::pipe;
 use std::io::{Stream, IoResult};
+use std::slice::bytes;
 
-use {ConnectParams, SslMode, NoSsl, PreferSsl, RequireSsl, TargetTcp, TargetUnix};
-use error::{PostgresConnectError, PgConnectStreamError, NoSslSupport, SslError, SocketError};
+use {ConnectParams, SslMode, NoSsl, PreferSsl, RequireSsl, DirectSsl, TargetTcp, TargetUnix};
+use error::{PostgresConnectError, PgConnectStreamError, NoSslSupport, NoAlpnProtocol,
+            DirectSslUnixSocket, SslError, SocketError};
 use message;
 use message::{SslRequest, WriteMessage};
 
 const DEFAULT_PORT: Port = 5432;
 
+/// The ALPN protocol identifier advertised during a `DirectSsl` handshake.
+const ALPN_PROTOCOL: &'static [u8] = b"postgresql";
+
+/// Socket-level options that can be changed once a connection
+/// has been established, regardless of whether it ended up TLS-wrapped.
+pub trait StreamOptions {
+    /// Sets the timeout for both reads and writes.
+    fn set_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Sets the timeout for reads.
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Sets the timeout for writes.
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>);
+
+    /// Puts the stream into or out of nonblocking mode.
+    fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()>;
+}
+
+/// A trait implemented by TLS backends capable of upgrading a raw
+/// `InternalStream` to an encrypted one.
+///
+/// The crate ships an OpenSSL-backed implementation by default, but
+/// implementing this trait lets callers plug in rustls,
+/// security-framework, schannel, or any other TLS stack instead.
+pub trait NegotiateSsl {
+    /// Upgrades `stream` to a TLS connection to `host`.
+    fn negotiate_ssl(&self, host: &str, stream: InternalStream)
+                     -> Result<Box<SslInnerStream+Send>, PostgresConnectError>;
+
+    /// Upgrades `stream` directly to a TLS connection to `host`, skipping
+    /// the `SslRequest` negotiation and instead confirming that the server
+    /// selected the `postgresql` ALPN protocol during the handshake.
+    fn negotiate_direct_ssl(&self, host: &str, stream: InternalStream)
+                            -> Result<Box<SslInnerStream+Send>, PostgresConnectError>;
+}
+
+/// The encrypted half of a `MaybeSslStream`: a `Stream` that also exposes
+/// `StreamOptions`, so timeouts and nonblocking mode can still be managed
+/// after the TLS handshake completes.
+pub trait SslInnerStream: Stream + StreamOptions {}
+
+impl<S: Stream + StreamOptions> SslInnerStream for S {}
+
+/// A client TLS identity: a private key paired with its certificate, used
+/// for mutual TLS.
+pub struct TlsIdentity {
+    pub key_file: Path,
+    pub cert_file: Path,
+}
+
+/// Configuration for the certificate verification and protocol policy
+/// used when negotiating a TLS connection.
+///
+/// This gives callers a structured way to opt into (or out of) verifying
+/// the server's certificate chain, pin a custom trust store, select the
+/// TLS protocol version, and present a client identity for mutual TLS,
+/// rather than having to configure an `SslContext` themselves before
+/// handing it to the crate.
+///
+/// This backend validates the certificate chain but, since the
+/// underlying OpenSSL bindings have no hostname verification support, it
+/// does not check the presented certificate against the server hostname;
+/// pin `ca_file` to a trust store that only issues certificates for
+/// hosts you trust if that matters for your deployment.
+pub struct TlsConfig {
+    /// Whether to verify the server's certificate chain. Defaults to
+    /// `ssl::SslVerifyPeer`; `ssl::SslVerifyNone` disables verification
+    /// entirely and should only be used outside of production.
+    pub verify_mode: ssl::SslVerifyMode,
+    /// A PEM file containing the certificate authorities to trust, in
+    /// place of the system trust store.
+    pub ca_file: Option<Path>,
+    /// The TLS protocol to negotiate. `ssl::Sslv23` (the default)
+    /// negotiates the highest version both sides support; a specific
+    /// method such as `ssl::Tlsv1_2` pins the connection to exactly that
+    /// version.
+    pub method: ssl::SslMethod,
+    /// A client certificate and key to present for mutual TLS.
+    pub identity: Option<TlsIdentity>,
+}
+
+impl TlsConfig {
+    /// Creates a `TlsConfig` that verifies the server's certificate chain
+    /// against the system trust store, negotiating the highest mutually
+    /// supported protocol version with no client identity.
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            verify_mode: ssl::SslVerifyPeer,
+            ca_file: None,
+            method: ssl::Sslv23,
+            identity: None,
+        }
+    }
+}
+
+/// The default `NegotiateSsl` implementation, backed by OpenSSL.
+pub struct OpenSsl {
+    ctx: ssl::SslContext,
+}
+
+impl OpenSsl {
+    /// Builds an `OpenSsl` negotiator, constructing and configuring the
+    /// underlying `SslContext` from `config`.
+    pub fn new(config: TlsConfig) -> Result<OpenSsl, PostgresConnectError> {
+        let mut builder = try!(ssl::SslContextBuilder::new(config.method).map_err(SslError));
+
+        builder.set_verify(config.verify_mode);
+
+        match config.ca_file {
+            Some(ref ca_file) => try!(builder.set_ca_file(ca_file).map_err(SslError)),
+            None => try!(builder.set_default_verify_paths().map_err(SslError)),
+        }
+        if let Some(ref identity) = config.identity {
+            try!(builder.set_private_key_file(&identity.key_file, x509::PEM).map_err(SslError));
+            try!(builder.set_certificate_file(&identity.cert_file, x509::PEM).map_err(SslError));
+        }
+
+        let ctx = builder.build();
+        // Advertised once, here, rather than on every `negotiate_direct_ssl`
+        // call, since it's the same protocol list for the lifetime of `ctx`.
+        ctx.set_alpn_protocols(&[ALPN_PROTOCOL]);
+
+        Ok(OpenSsl { ctx: ctx })
+    }
+}
+
+impl NegotiateSsl for OpenSsl {
+    fn negotiate_ssl(&self, _host: &str, stream: InternalStream)
+                     -> Result<Box<SslInnerStream+Send>, PostgresConnectError> {
+        match ssl::SslStream::new(&self.ctx, stream) {
+            Ok(stream) => Ok(box stream as Box<SslInnerStream+Send>),
+            Err(err) => Err(SslError(err)),
+        }
+    }
+
+    fn negotiate_direct_ssl(&self, _host: &str, stream: InternalStream)
+                            -> Result<Box<SslInnerStream+Send>, PostgresConnectError> {
+        let stream = match ssl::SslStream::new(&self.ctx, stream) {
+            Ok(stream) => stream,
+            Err(err) => return Err(SslError(err)),
+        };
+
+        match stream.ssl().selected_alpn_protocol() {
+            Some(proto) if proto == ALPN_PROTOCOL => Ok(box stream as Box<SslInnerStream+Send>),
+            _ => Err(NoAlpnProtocol),
+        }
+    }
+}
+
+impl<S: StreamOptions> StreamOptions for ssl::SslStream<S> {
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.get_mut().set_timeout(timeout_ms)
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.get_mut().set_read_timeout(timeout_ms)
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.get_mut().set_write_timeout(timeout_ms)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()> {
+        self.get_mut().set_nonblocking(nonblocking)
+    }
+}
+
 pub enum MaybeSslStream<S> {
-    SslStream(ssl::SslStream<S>),
+    SslStream(Box<SslInnerStream+Send>),
     NormalStream(S),
 }
 
@@ -41,6 +213,36 @@ impl<S: Stream> Writer for MaybeSslStream<S> {
     }
 }
 
+impl<S: StreamOptions> StreamOptions for MaybeSslStream<S> {
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            SslStream(ref mut s) => s.set_timeout(timeout_ms),
+            NormalStream(ref mut s) => s.set_timeout(timeout_ms),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            SslStream(ref mut s) => s.set_read_timeout(timeout_ms),
+            NormalStream(ref mut s) => s.set_read_timeout(timeout_ms),
+        }
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            SslStream(ref mut s) => s.set_write_timeout(timeout_ms),
+            NormalStream(ref mut s) => s.set_write_timeout(timeout_ms),
+        }
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()> {
+        match *self {
+            SslStream(ref mut s) => s.set_nonblocking(nonblocking),
+            NormalStream(ref mut s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
 pub enum InternalStream {
     TcpStream(tcp::TcpStream),
     UnixStream(pipe::UnixStream),
@@ -71,12 +273,245 @@ impl Writer for InternalStream {
     }
 }
 
+impl StreamOptions for InternalStream {
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            TcpStream(ref mut s) => s.set_timeout(timeout_ms),
+            UnixStream(ref mut s) => s.set_timeout(timeout_ms),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            TcpStream(ref mut s) => s.set_read_timeout(timeout_ms),
+            UnixStream(ref mut s) => s.set_read_timeout(timeout_ms),
+        }
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        match *self {
+            TcpStream(ref mut s) => s.set_write_timeout(timeout_ms),
+            UnixStream(ref mut s) => s.set_write_timeout(timeout_ms),
+        }
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()> {
+        match *self {
+            TcpStream(ref mut s) => s.set_nonblocking(nonblocking),
+            UnixStream(ref mut s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+const BUF_SIZE: uint = 8 * 1024;
+
+/// A buffering wrapper around a `MaybeSslStream` (or any `Stream`).
+///
+/// Reads are filled from the underlying stream in `BUF_SIZE` chunks rather
+/// than one syscall per protocol message, and writes are accumulated until
+/// `flush` is called or the buffer would overflow, which cuts down on the
+/// syscall overhead of sending and receiving many small messages. A single
+/// write of at least `BUF_SIZE` bytes goes straight to the underlying stream.
+pub struct BufStream<S> {
+    inner: S,
+    rbuf: Vec<u8>,
+    rpos: uint,
+    rcap: uint,
+    wbuf: Vec<u8>,
+}
+
+impl<S: Stream> BufStream<S> {
+    /// Wraps `inner` in a buffered reader/writer.
+    pub fn new(inner: S) -> BufStream<S> {
+        BufStream {
+            inner: inner,
+            rbuf: Vec::from_elem(BUF_SIZE, 0u8),
+            rpos: 0,
+            rcap: 0,
+            wbuf: Vec::with_capacity(BUF_SIZE),
+        }
+    }
+}
+
+impl<S: Stream> Reader for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.rpos == self.rcap {
+            self.rcap = try!(self.inner.read(self.rbuf.as_mut_slice()));
+            self.rpos = 0;
+        }
+
+        let n = cmp::min(buf.len(), self.rcap - self.rpos);
+        bytes::copy_memory(buf, self.rbuf.slice(self.rpos, self.rpos + n));
+        self.rpos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Stream> Writer for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if self.wbuf.len() + buf.len() > self.wbuf.capacity() {
+            try!(self.flush());
+        }
+
+        if buf.len() >= self.wbuf.capacity() {
+            self.inner.write(buf)
+        } else {
+            self.wbuf.push_all(buf);
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        if !self.wbuf.is_empty() {
+            try!(self.inner.write(self.wbuf.as_slice()));
+            self.wbuf.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+impl<S: StreamOptions> StreamOptions for BufStream<S> {
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.inner.set_read_timeout(timeout_ms)
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.inner.set_write_timeout(timeout_ms)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> IoResult<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, Reader, Writer, IoResult};
+
+    use super::{BufStream, BUF_SIZE};
+
+    struct ChannelStream {
+        input: MemReader,
+        output: Vec<u8>,
+    }
+
+    impl ChannelStream {
+        fn new(input: Vec<u8>) -> ChannelStream {
+            ChannelStream {
+                input: MemReader::new(input),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Reader for ChannelStream {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Writer for ChannelStream {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.output.push_all(buf);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    impl ::std::io::Stream for ChannelStream {}
+
+    #[test]
+    fn read_splits_across_buffer_boundary() {
+        let data: Vec<u8> = range(0u, BUF_SIZE + 4).map(|i| i as u8).collect();
+        let mut stream = BufStream::new(ChannelStream::new(data.clone()));
+
+        let mut first = [0u8, ..BUF_SIZE];
+        assert_eq!(stream.read(&mut first).unwrap(), BUF_SIZE);
+        assert_eq!(first.as_slice(), data.slice(0, BUF_SIZE));
+
+        let mut second = [0u8, ..4];
+        assert_eq!(stream.read(&mut second).unwrap(), 4);
+        assert_eq!(second.as_slice(), data.slice(BUF_SIZE, BUF_SIZE + 4));
+    }
+
+    #[test]
+    fn read_refills_after_exhaustion() {
+        let mut stream = BufStream::new(ChannelStream::new(vec![1, 2, 3]));
+
+        let mut buf = [0u8, ..3];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(buf.as_slice(), [1, 2, 3].as_slice());
+
+        // The underlying stream is now empty; refilling should surface EOF
+        // rather than replaying the stale buffer.
+        assert!(stream.read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn write_coalesces_small_writes_until_flush() {
+        let mut stream = BufStream::new(ChannelStream::new(vec![]));
+
+        stream.write(&[1, 2, 3]).unwrap();
+        stream.write(&[4, 5, 6]).unwrap();
+        assert_eq!(stream.inner.output, vec![]);
+
+        stream.flush().unwrap();
+        assert_eq!(stream.inner.output, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn write_overflowing_buffer_flushes_first() {
+        let mut stream = BufStream::new(ChannelStream::new(vec![]));
+
+        stream.write(&[1, 2, 3]).unwrap();
+        stream.write(Vec::from_elem(BUF_SIZE, 7u8).as_slice()).unwrap();
+
+        let mut expected = vec![1, 2, 3];
+        expected.push_all(Vec::from_elem(BUF_SIZE, 7u8).as_slice());
+        assert_eq!(stream.inner.output, expected);
+    }
+
+    #[test]
+    fn write_at_least_capacity_bypasses_buffer() {
+        let mut stream = BufStream::new(ChannelStream::new(vec![]));
+
+        let big = Vec::from_elem(BUF_SIZE, 9u8);
+        stream.write(big.as_slice()).unwrap();
+
+        // A write `>= capacity` should go straight to the underlying stream
+        // without needing an explicit `flush`.
+        assert_eq!(stream.inner.output, big);
+    }
+}
+
 fn open_socket(params: &ConnectParams)
                -> Result<InternalStream, PostgresConnectError> {
     let port = params.port.unwrap_or(DEFAULT_PORT);
     let socket = match params.target {
-        TargetTcp(ref host) =>
-            tcp::TcpStream::connect(host[], port).map(TcpStream),
+        TargetTcp(ref host) => {
+            let stream = match params.connect_timeout {
+                Some(timeout_ms) =>
+                    tcp::TcpStream::connect_timeout(host[], port, timeout_ms),
+                None => tcp::TcpStream::connect(host[], port),
+            };
+            let mut stream = try!(stream.map_err(SocketError));
+
+            if let Some(nodelay) = params.nodelay {
+                try!(stream.set_nodelay(nodelay).map_err(SocketError));
+            }
+            if let Some(keepalive) = params.keepalive {
+                try!(stream.set_keepalive(Some(keepalive)).map_err(SocketError));
+            }
+
+            return Ok(TcpStream(stream));
+        }
         TargetUnix(ref path) => {
             let mut path = path.clone();
             path.push(format!(".s.PGSQL.{}", port));
@@ -86,14 +521,40 @@ fn open_socket(params: &ConnectParams)
     socket.map_err(SocketError)
 }
 
+fn target_host(params: &ConnectParams) -> &str {
+    match params.target {
+        TargetTcp(ref host) => host[],
+        TargetUnix(_) => "",
+    }
+}
+
 pub fn initialize_stream(params: &ConnectParams, ssl: &SslMode)
-                         -> Result<MaybeSslStream<InternalStream>, PostgresConnectError> {
+                         -> Result<BufStream<MaybeSslStream<InternalStream>>, PostgresConnectError> {
+    connect(params, ssl).map(BufStream::new)
+}
+
+fn connect(params: &ConnectParams, ssl: &SslMode)
+          -> Result<MaybeSslStream<InternalStream>, PostgresConnectError> {
     let mut socket = try!(open_socket(params));
+    socket.set_read_timeout(params.read_timeout);
 
-    let (ssl_required, ctx) = match *ssl {
+    let (ssl_required, negotiator) = match *ssl {
         NoSsl => return Ok(NormalStream(socket)),
-        PreferSsl(ref ctx) => (false, ctx),
-        RequireSsl(ref ctx) => (true, ctx)
+        DirectSsl(ref negotiator) => {
+            // Direct SSL exists to let a TCP load balancer or proxy confirm
+            // the ALPN-negotiated protocol up front; it has no meaning over
+            // a local Unix socket, which has no such intermediary.
+            let host = match params.target {
+                TargetTcp(ref host) => host[],
+                TargetUnix(_) => return Err(DirectSslUnixSocket),
+            };
+            return match negotiator.negotiate_direct_ssl(host, socket) {
+                Ok(stream) => Ok(SslStream(stream)),
+                Err(err) => Err(err),
+            };
+        }
+        PreferSsl(ref negotiator) => (false, negotiator),
+        RequireSsl(ref negotiator) => (true, negotiator)
     };
 
     try_pg_conn!(socket.write_message(&SslRequest { code: message::SSL_CODE }));
@@ -107,8 +568,8 @@ pub fn initialize_stream(params: &ConnectParams, ssl: &SslMode)
         }
     }
 
-    match ssl::SslStream::new(ctx, socket) {
+    match negotiator.negotiate_ssl(target_host(params), socket) {
         Ok(stream) => Ok(SslStream(stream)),
-        Err(err) => Err(SslError(err))
+        Err(err) => Err(err),
     }
 }